@@ -0,0 +1,162 @@
+use aes_gcm::aead::{Aead, NewAead};
+use aes_gcm::Aes256Gcm;
+use anyhow::Result;
+use argon2::{Argon2, PasswordHasher};
+use chacha20poly1305::ChaCha20Poly1305;
+use password_hash::SaltString;
+use rkyv::{Archive, Deserialize, Serialize};
+
+/// Number of plaintext bytes encrypted under a single page nonce.
+///
+/// Matches bao's leaf chunk size so page boundaries line up with the
+/// outboard, keeping slicing/seeking meaningful on ciphertext.
+pub const PAGE_SIZE: usize = 1024;
+
+/// Overhead a page's AEAD tag adds to its ciphertext, on top of the
+/// plaintext page length. [`ciphertext_page_len`] adds none of it for
+/// [`EncryptionType::None`].
+pub const TAG_LEN: usize = 16;
+
+/// Length of the on-disk ciphertext for a plaintext page of `plaintext_len`
+/// bytes, used to seek directly to a given page's bytes on disk.
+pub fn ciphertext_page_len(ty: EncryptionType, plaintext_len: usize) -> usize {
+    match ty {
+        EncryptionType::None => plaintext_len,
+        EncryptionType::Aes256Gcm | EncryptionType::ChaCha20Poly1305 => plaintext_len + TAG_LEN,
+    }
+}
+
+/// Which AEAD, if any, a stream's content is encrypted under.
+///
+/// `None` is the default so existing, unencrypted stores keep working.
+#[derive(Archive, Deserialize, Serialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u8)]
+#[cfg_attr(feature = "serde-derive", derive(serde::Deserialize, serde::Serialize))]
+pub enum EncryptionType {
+    None = 0,
+    Aes256Gcm = 1,
+    ChaCha20Poly1305 = 2,
+}
+
+impl Default for EncryptionType {
+    fn default() -> Self {
+        Self::None
+    }
+}
+
+/// A 32-byte content key derived from a passphrase and a per-stream salt.
+pub struct ContentKey([u8; 32]);
+
+impl ContentKey {
+    /// Derives a content key from `passphrase` and `salt` using Argon2id.
+    pub fn derive(passphrase: &[u8], salt: &[u8; 32]) -> Result<Self> {
+        let salt = SaltString::b64_encode(salt).map_err(|e| anyhow::anyhow!("{}", e))?;
+        let hash = Argon2::default()
+            .hash_password(passphrase, &salt)
+            .map_err(|e| anyhow::anyhow!("{}", e))?;
+        let hash = hash.hash.ok_or_else(|| anyhow::anyhow!("argon2 produced no hash"))?;
+        let mut key = [0; 32];
+        key.copy_from_slice(&hash.as_bytes()[..32]);
+        Ok(Self(key))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+/// Builds the 12-byte AEAD nonce for `page_index` of `stream`.
+///
+/// Binding the nonce to both the stream id and the page index means a key
+/// can safely be reused across pages and across streams that share a
+/// passphrase.
+fn page_nonce(stream: u64, page_index: u64) -> [u8; 12] {
+    let mut nonce = [0; 12];
+    nonce[..8].copy_from_slice(&stream.to_le_bytes());
+    nonce[8..].copy_from_slice(&page_index.to_le_bytes()[..4]);
+    nonce
+}
+
+/// Encrypts a single `PAGE_SIZE` (or shorter, final) plaintext page.
+pub fn encrypt_page(
+    ty: EncryptionType,
+    key: &ContentKey,
+    stream: u64,
+    page_index: u64,
+    plaintext: &[u8],
+) -> Result<Vec<u8>> {
+    let nonce = page_nonce(stream, page_index);
+    match ty {
+        EncryptionType::None => Ok(plaintext.to_vec()),
+        EncryptionType::Aes256Gcm => {
+            let cipher = Aes256Gcm::new(key.as_bytes().into());
+            cipher
+                .encrypt(&nonce.into(), plaintext)
+                .map_err(|e| anyhow::anyhow!("{}", e))
+        }
+        EncryptionType::ChaCha20Poly1305 => {
+            let cipher = ChaCha20Poly1305::new(key.as_bytes().into());
+            cipher
+                .encrypt(&nonce.into(), plaintext)
+                .map_err(|e| anyhow::anyhow!("{}", e))
+        }
+    }
+}
+
+/// Decrypts a single page produced by [`encrypt_page`].
+pub fn decrypt_page(
+    ty: EncryptionType,
+    key: &ContentKey,
+    stream: u64,
+    page_index: u64,
+    ciphertext: &[u8],
+) -> Result<Vec<u8>> {
+    let nonce = page_nonce(stream, page_index);
+    match ty {
+        EncryptionType::None => Ok(ciphertext.to_vec()),
+        EncryptionType::Aes256Gcm => {
+            let cipher = Aes256Gcm::new(key.as_bytes().into());
+            cipher
+                .decrypt(&nonce.into(), ciphertext)
+                .map_err(|e| anyhow::anyhow!("{}", e))
+        }
+        EncryptionType::ChaCha20Poly1305 => {
+            let cipher = ChaCha20Poly1305::new(key.as_bytes().into());
+            cipher
+                .decrypt(&nonce.into(), ciphertext)
+                .map_err(|e| anyhow::anyhow!("{}", e))
+        }
+    }
+}
+
+/// Generates a fresh random per-stream salt for content-key derivation.
+pub fn generate_salt() -> [u8; 32] {
+    let mut salt = [0; 32];
+    getrandom::getrandom(&mut salt).expect("getrandom failed");
+    salt
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip_aes_gcm() {
+        let salt = generate_salt();
+        let key = ContentKey::derive(b"correct horse battery staple", &salt).unwrap();
+        let plaintext = b"hello stream";
+        let ct = encrypt_page(EncryptionType::Aes256Gcm, &key, 42, 0, plaintext).unwrap();
+        let pt = decrypt_page(EncryptionType::Aes256Gcm, &key, 42, 0, &ct).unwrap();
+        assert_eq!(pt, plaintext);
+    }
+
+    #[test]
+    fn roundtrip_chacha20poly1305() {
+        let salt = generate_salt();
+        let key = ContentKey::derive(b"correct horse battery staple", &salt).unwrap();
+        let plaintext = b"hello stream";
+        let ct = encrypt_page(EncryptionType::ChaCha20Poly1305, &key, 42, 0, plaintext).unwrap();
+        let pt = decrypt_page(EncryptionType::ChaCha20Poly1305, &key, 42, 0, &ct).unwrap();
+        assert_eq!(pt, plaintext);
+    }
+}
@@ -0,0 +1,135 @@
+use crate::Signer;
+use anyhow::Result;
+use ed25519_dalek::{PublicKey, Signature};
+use rkyv::{Archive, Deserialize, Serialize};
+
+/// One hop in a stream's signing-key rotation chain: `old_key` authorizes
+/// `new_key` to sign heads from `len_at_rotation` onwards, so a compromised
+/// or retired key can be retired without abandoning the stream.
+#[derive(Archive, Deserialize, Serialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde-derive", derive(serde::Deserialize, serde::Serialize))]
+pub struct Rotation {
+    old_key: [u8; 32],
+    new_key: [u8; 32],
+    len_at_rotation: u64,
+    #[cfg_attr(feature = "serde-derive", serde(with = "serde_big_array::BigArray"))]
+    sig: [u8; 64],
+}
+
+impl Rotation {
+    /// Signs a rotation handing off authority from `old` to `new_key`,
+    /// effective at `len_at_rotation` bytes into the stream.
+    pub fn sign<S: Signer>(old: &S, new_key: PublicKey, len_at_rotation: u64) -> Result<Self> {
+        let old_key = old.public_key().to_bytes();
+        let new_key = new_key.to_bytes();
+        let msg = Self::signed_bytes(&old_key, &new_key, len_at_rotation);
+        let sig = old.sign(&msg)?;
+        Ok(Self {
+            old_key,
+            new_key,
+            len_at_rotation,
+            sig,
+        })
+    }
+
+    fn signed_bytes(old_key: &[u8; 32], new_key: &[u8; 32], len_at_rotation: u64) -> Vec<u8> {
+        let mut msg = Vec::with_capacity(32 + 32 + 8);
+        msg.extend_from_slice(old_key);
+        msg.extend_from_slice(new_key);
+        msg.extend_from_slice(&len_at_rotation.to_le_bytes());
+        msg
+    }
+
+    pub fn old_key(&self) -> Result<PublicKey> {
+        Ok(PublicKey::from_bytes(&self.old_key)?)
+    }
+
+    pub fn new_key(&self) -> Result<PublicKey> {
+        Ok(PublicKey::from_bytes(&self.new_key)?)
+    }
+
+    pub fn len_at_rotation(&self) -> u64 {
+        self.len_at_rotation
+    }
+
+    /// Checks that this hop was actually signed by `old_key`.
+    pub fn verify(&self) -> Result<()> {
+        let msg = Self::signed_bytes(&self.old_key, &self.new_key, self.len_at_rotation);
+        let sig = Signature::from(self.sig);
+        self.old_key()?.verify_strict(&msg, &sig)?;
+        Ok(())
+    }
+}
+
+/// Walks a rotation chain from the genesis `peer` key and returns the
+/// currently active signing key, i.e. the `new_key` of the last hop
+/// (whatever its `len_at_rotation`), after checking every hop's signature and
+/// that each hop's `old_key` matches the previous hop's `new_key`.
+pub fn active_key(genesis: PublicKey, chain: &[Rotation]) -> Result<PublicKey> {
+    let mut current = genesis;
+    for rotation in chain {
+        rotation.verify()?;
+        if rotation.old_key()? != current {
+            return Err(anyhow::anyhow!("broken rotation chain"));
+        }
+        current = rotation.new_key()?;
+    }
+    Ok(current)
+}
+
+/// Returns whichever key was authorized to sign a head of length
+/// `head_len`, by walking the chain and checking every hop's signature and
+/// continuity along the way.
+pub fn key_at(genesis: PublicKey, chain: &[Rotation], head_len: u64) -> Result<PublicKey> {
+    let mut current = genesis;
+    for rotation in chain {
+        if head_len < rotation.len_at_rotation() {
+            break;
+        }
+        rotation.verify()?;
+        if rotation.old_key()? != current {
+            return Err(anyhow::anyhow!("broken rotation chain"));
+        }
+        current = rotation.new_key()?;
+    }
+    Ok(current)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::Keypair;
+
+    fn keypair() -> Keypair {
+        Keypair::generate(&mut rand_core::OsRng)
+    }
+
+    #[test]
+    fn active_key_with_no_rotations_is_genesis() {
+        let genesis = keypair();
+        assert_eq!(active_key(genesis.public, &[]).unwrap(), genesis.public);
+    }
+
+    #[test]
+    fn key_at_switches_at_rotation_length() {
+        let genesis = keypair();
+        let rotated = keypair();
+        let rotation = Rotation::sign(&genesis, rotated.public, 100).unwrap();
+        let chain = [rotation];
+
+        assert_eq!(key_at(genesis.public, &chain, 0).unwrap(), genesis.public);
+        assert_eq!(key_at(genesis.public, &chain, 99).unwrap(), genesis.public);
+        assert_eq!(key_at(genesis.public, &chain, 100).unwrap(), rotated.public);
+        assert_eq!(active_key(genesis.public, &chain).unwrap(), rotated.public);
+    }
+
+    #[test]
+    fn broken_chain_is_rejected() {
+        let genesis = keypair();
+        let unrelated = keypair();
+        let rotated = keypair();
+        // signed by a key that isn't genesis -- breaks continuity.
+        let rotation = Rotation::sign(&unrelated, rotated.public, 0).unwrap();
+        assert!(active_key(genesis.public, &[rotation]).is_err());
+    }
+}
@@ -1,5 +1,7 @@
+use crate::encryption::{generate_salt, EncryptionType};
+use crate::rotation::Rotation;
 use anyhow::Result;
-use ed25519_dalek::{Keypair, PublicKey, Signature, Signer};
+use ed25519_dalek::{Keypair, PublicKey, Signature};
 use fnv::FnvHashSet;
 use parking_lot::Mutex;
 use rkyv::ser::serializers::AllocSerializer;
@@ -46,6 +48,37 @@ impl std::str::FromStr for StreamId {
     }
 }
 
+/// Produces ed25519 signatures over head bytes without requiring the
+/// private key to live in this process as a raw `Keypair`.
+///
+/// Implement this to plug in an SSH agent, a PKCS#11/HSM, or a remote
+/// signing service; `SignedHead::sign` and everything that writes local
+/// streams is generic over it.
+pub trait Signer {
+    fn public_key(&self) -> PublicKey;
+    fn sign(&self, msg: &[u8]) -> Result<[u8; 64]>;
+}
+
+impl Signer for Keypair {
+    fn public_key(&self) -> PublicKey {
+        self.public
+    }
+
+    fn sign(&self, msg: &[u8]) -> Result<[u8; 64]> {
+        Ok(ed25519_dalek::Signer::sign(self, msg).to_bytes())
+    }
+}
+
+impl<S: Signer> Signer for Arc<S> {
+    fn public_key(&self) -> PublicKey {
+        (**self).public_key()
+    }
+
+    fn sign(&self, msg: &[u8]) -> Result<[u8; 64]> {
+        (**self).sign(msg)
+    }
+}
+
 impl StreamId {
     pub fn new(peer: [u8; 32], stream: u64) -> Self {
         Self { peer, stream }
@@ -133,12 +166,68 @@ impl SignedHead {
         if id != self.head().id() {
             return Err(anyhow::anyhow!("missmatched stream id"));
         }
+        self.verify_self()
+    }
+
+    /// Like [`verify`](Self::verify), but checks the signature against an
+    /// explicitly supplied `key` rather than `id.peer()`. Used when a
+    /// stream's signing key may have rotated away from its genesis peer key.
+    pub fn verify_with_key(&self, id: &StreamId, key: &PublicKey) -> Result<()> {
+        if id != self.head().id() {
+            return Err(anyhow::anyhow!("missmatched stream id"));
+        }
+        let sig = Signature::from(self.sig);
+        key.verify_strict(self.head.as_bytes(), &sig)?;
+        Ok(())
+    }
+
+    /// Checks that this head's signature was produced by its own embedded
+    /// `StreamId`'s peer key, without requiring the caller to already know
+    /// which stream it claims to be.
+    fn verify_self(&self) -> Result<()> {
         let sig = Signature::from(self.sig);
-        id.peer().verify_strict(self.head.as_bytes(), &sig)?;
+        self.head
+            .id()
+            .peer()
+            .verify_strict(self.head.as_bytes(), &sig)?;
         Ok(())
     }
 }
 
+/// Format-version byte prepended to every CBOR-encoded message, so readers
+/// can reject encodings from an incompatible future version up front.
+const WIRE_FORMAT_VERSION: u8 = 1;
+
+#[cfg(feature = "serde-derive")]
+impl SignedHead {
+    /// Encodes this head as a self-describing, length-delimited CBOR
+    /// message suitable for sending between replication peers running
+    /// different builds (unlike the rkyv on-disk format, this is
+    /// endianness- and layout-independent).
+    pub fn to_cbor(&self) -> Result<Vec<u8>> {
+        let mut buf = vec![WIRE_FORMAT_VERSION];
+        serde_cbor::to_writer(&mut buf, self)?;
+        Ok(buf)
+    }
+
+    /// Decodes a [`SignedHead`] produced by [`SignedHead::to_cbor`].
+    ///
+    /// This does *not* verify the embedded signature: a rotated stream's
+    /// head is signed by whatever key was active at `head.len()`, not
+    /// necessarily `id().peer()`, so checking that requires the stream's
+    /// rotation chain. Callers must verify the result themselves, e.g. via
+    /// `Stream::verify_head` once the matching chain is known.
+    pub fn from_cbor(bytes: &[u8]) -> Result<Self> {
+        let (version, body) = bytes
+            .split_first()
+            .ok_or_else(|| anyhow::anyhow!("empty message"))?;
+        if *version != WIRE_FORMAT_VERSION {
+            return Err(anyhow::anyhow!("unsupported wire format version {}", version));
+        }
+        Ok(serde_cbor::from_slice(body)?)
+    }
+}
+
 impl SignedHead {
     pub(crate) fn new(id: StreamId) -> Self {
         Self {
@@ -147,9 +236,14 @@ impl SignedHead {
         }
     }
 
-    pub(crate) fn sign(&mut self, key: &Keypair) {
-        debug_assert_eq!(key.public, self.head.id().peer());
-        self.sig = key.sign(self.head.as_bytes()).to_bytes();
+    pub(crate) fn sign<S: Signer>(&mut self, signer: &S) -> Result<()> {
+        // `signer` need not be the genesis `self.head.id().peer()` key: once
+        // a stream has rotated (see `crate::rotation`), local appends are
+        // signed with the currently active key instead, and verifiers walk
+        // the rotation chain (`Stream::verify_head`) rather than checking
+        // against the genesis key directly.
+        self.sig = signer.sign(self.head.as_bytes())?;
+        Ok(())
     }
 
     pub(crate) fn set_signature(&mut self, sig: [u8; 64]) -> Result<()> {
@@ -167,19 +261,75 @@ impl SignedHead {
 pub struct Stream {
     pub(crate) head: SignedHead,
     pub(crate) outboard: Vec<u8>,
+    pub(crate) encryption: EncryptionType,
+    pub(crate) salt: [u8; 32],
+    /// Ordered chain of signing-key rotations, starting from the genesis
+    /// key `head.id().peer()`. Empty for a stream that has never rotated.
+    pub(crate) rotations: Vec<Rotation>,
 }
 
 impl Stream {
     pub fn head(&self) -> &Head {
         self.head.head()
     }
+
+    pub fn encryption(&self) -> EncryptionType {
+        self.encryption
+    }
+
+    pub fn salt(&self) -> &[u8; 32] {
+        &self.salt
+    }
+
+    pub fn rotations(&self) -> &[Rotation] {
+        &self.rotations
+    }
+
+    /// The currently active signing key, walking the rotation chain from
+    /// the genesis `StreamId` peer key.
+    pub fn active_key(&self) -> Result<PublicKey> {
+        crate::rotation::active_key(self.head.head().id().peer(), &self.rotations)
+    }
+
+    /// Verifies `head` was signed by whichever key was authorized at
+    /// `head.len()`, walking this stream's rotation chain.
+    pub fn verify_head(&self, head: &SignedHead) -> Result<()> {
+        let id = *self.head.head().id();
+        let key = crate::rotation::key_at(id.peer(), &self.rotations, head.head().len())?;
+        head.verify_with_key(&id, &key)
+    }
 }
 
 impl Stream {
     pub(crate) fn new(id: StreamId) -> Self {
+        Self::new_with_encryption(id, EncryptionType::None)
+    }
+
+    pub(crate) fn new_with_encryption(id: StreamId, encryption: EncryptionType) -> Self {
+        let salt = if encryption == EncryptionType::None {
+            [0; 32]
+        } else {
+            generate_salt()
+        };
+        Self::new_with_encryption_and_salt(id, encryption, salt)
+    }
+
+    /// Like [`Stream::new_with_encryption`], but pins the content-key salt to
+    /// `salt` instead of generating a fresh random one. Used when replicating
+    /// an encrypted stream: the replica must derive the same content key as
+    /// the origin, which means reusing the origin's salt rather than minting
+    /// a new one.
+    pub(crate) fn new_with_encryption_and_salt(
+        id: StreamId,
+        encryption: EncryptionType,
+        salt: [u8; 32],
+    ) -> Self {
         Self {
             head: SignedHead::new(id),
             outboard: vec![0, 0, 0, 0, 0, 0, 0, 0],
+            encryption,
+            salt,
+            rotations: Vec::new(),
         }
     }
 
@@ -188,6 +338,45 @@ impl Stream {
         ser.serialize_value(self).unwrap();
         Ok(ser.into_serializer().into_inner())
     }
+
+    /// Encodes this stream as a sled record: a schema-version byte followed
+    /// by its rkyv bytes. See [`check_stream_schema`] for the reader side.
+    pub(crate) fn to_sled_bytes(&self) -> Result<Vec<u8>> {
+        let mut bytes = vec![STREAM_SCHEMA_VERSION];
+        bytes.extend_from_slice(&self.to_bytes()?);
+        Ok(bytes)
+    }
+}
+
+/// Schema version prepended to every `Stream` record written to sled.
+///
+/// rkyv has no built-in schema evolution, so `Stream`'s archived layout
+/// (e.g. the `encryption`/`salt`/`rotations` fields added alongside key
+/// rotation and at-rest encryption) must never change without bumping
+/// this: reading a record written at a different version via
+/// `rkyv::archived_root` would silently reinterpret its trailing bytes as
+/// whatever fields the *current* layout expects, which is undefined
+/// behavior, not a graceful default. Bump this whenever `Stream`'s
+/// archived fields change, and see [`check_stream_schema`].
+const STREAM_SCHEMA_VERSION: u8 = 1;
+
+/// Strips and checks the [`STREAM_SCHEMA_VERSION`] byte prepended by
+/// [`Stream::to_sled_bytes`], returning the remaining rkyv-encoded bytes.
+/// Errors instead of reinterpreting the bytes if the leading byte is not
+/// [`STREAM_SCHEMA_VERSION`]; a record that predates versioning is caught
+/// only when its first byte happens to differ from that value.
+pub(crate) fn check_stream_schema(bytes: &[u8]) -> Result<&[u8]> {
+    let (version, body) = bytes
+        .split_first()
+        .ok_or_else(|| anyhow::anyhow!("empty stream record"))?;
+    if *version != STREAM_SCHEMA_VERSION {
+        return Err(anyhow::anyhow!(
+            "stream record has schema version {}, this build expects {} -- migrate the store before upgrading",
+            version,
+            STREAM_SCHEMA_VERSION
+        ));
+    }
+    Ok(body)
 }
 
 #[derive(Archive, Deserialize, Serialize, Clone, Debug, Default, Eq, PartialEq)]
@@ -213,6 +402,32 @@ impl Slice {
     }
 }
 
+#[cfg(feature = "serde-derive")]
+impl Slice {
+    /// Encodes this slice as a self-describing, length-delimited CBOR
+    /// message for the over-the-wire replication path; see
+    /// [`SignedHead::to_cbor`] for the rationale.
+    pub fn to_cbor(&self) -> Result<Vec<u8>> {
+        let mut buf = vec![WIRE_FORMAT_VERSION];
+        serde_cbor::to_writer(&mut buf, self)?;
+        Ok(buf)
+    }
+
+    /// Decodes a [`Slice`] produced by [`Slice::to_cbor`]. As with
+    /// [`SignedHead::from_cbor`], the embedded head's signature is not
+    /// verified here; callers must verify it against the relevant stream's
+    /// rotation chain before trusting `data`.
+    pub fn from_cbor(bytes: &[u8]) -> Result<Self> {
+        let (version, body) = bytes
+            .split_first()
+            .ok_or_else(|| anyhow::anyhow!("empty message"))?;
+        if *version != WIRE_FORMAT_VERSION {
+            return Err(anyhow::anyhow!("unsupported wire format version {}", version));
+        }
+        Ok(serde_cbor::from_slice(body)?)
+    }
+}
+
 pub(crate) struct StreamLock {
     id: StreamId,
     locks: Arc<Mutex<FnvHashSet<StreamId>>>,
@@ -249,8 +464,35 @@ mod tests {
                 sig: [0; 64],
             },
             outboard,
+            encryption: EncryptionType::None,
+            salt: [0; 32],
+            rotations: Vec::new(),
         };
         let actual = Stream::new(id);
         assert_eq!(actual, expect);
     }
+
+    #[cfg(feature = "serde-derive")]
+    #[test]
+    fn slice_cbor_roundtrip() {
+        let keypair = Keypair::generate(&mut rand_core::OsRng);
+        let id = StreamId::new(keypair.public.to_bytes(), 7);
+        let mut head = SignedHead::new(id);
+        head.head.hash = [9; 32];
+        head.head.len = 3;
+        head.sign(&keypair).unwrap();
+
+        let encoded = head.to_cbor().unwrap();
+        let decoded = SignedHead::from_cbor(&encoded).unwrap();
+        assert_eq!(decoded, head);
+        decoded.verify(&id).unwrap();
+
+        let slice = Slice {
+            head,
+            data: vec![1, 2, 3],
+        };
+        let encoded = slice.to_cbor().unwrap();
+        let decoded = Slice::from_cbor(&encoded).unwrap();
+        assert_eq!(decoded, slice);
+    }
 }
@@ -0,0 +1,251 @@
+//! Local append/read paths for a single stream's content on disk.
+//!
+//! Both [`StreamWriter`] and [`StreamReader`] work in terms of plaintext:
+//! when a stream is encrypted (`Stream::encryption() != EncryptionType::None`)
+//! they transparently encrypt/decrypt fixed-size pages (see
+//! `crate::encryption`) so that the bytes on disk, and the bao hash/outboard
+//! committed to in the `Stream`'s head, are always over ciphertext.
+use crate::encryption::{ciphertext_page_len, decrypt_page, encrypt_page, ContentKey, PAGE_SIZE};
+use crate::stream::StreamLock;
+use crate::{EncryptionType, Head, Signer, SignedHead, Stream};
+use anyhow::Result;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Appends plaintext to a stream, encrypting it page-by-page if the stream
+/// is encrypted, and recomputing the bao outboard/hash over the ciphertext
+/// on disk once the append session finishes.
+///
+/// `S` is the local head signer: `Arc<K>` for `StreamStorage::append_local_stream`,
+/// `()` for `StreamStorage::append_replicated_stream`, which instead accepts
+/// an already-verified head from the peer in [`StreamWriter::finish_with_head`].
+pub struct StreamWriter<S> {
+    path: PathBuf,
+    db: sled::Db,
+    stream: Stream,
+    #[allow(dead_code)]
+    lock: StreamLock,
+    signer: S,
+    content_key: Option<ContentKey>,
+    pending: Vec<u8>,
+}
+
+impl<S> StreamWriter<S> {
+    /// `content_key`, if any, is applied to encrypt plaintext pages as they
+    /// land (see `write_page`). Replicated writes pass `None` unconditionally
+    /// regardless of `stream.encryption()`: their bytes are a peer's slice of
+    /// the stream's actual on-disk content (already ciphertext, if the
+    /// stream is encrypted) and must be written through verbatim, not
+    /// encrypted a second time.
+    pub(crate) fn new(
+        path: &Path,
+        stream: Stream,
+        lock: StreamLock,
+        db: sled::Db,
+        signer: S,
+        content_key: Option<ContentKey>,
+    ) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            db,
+            stream,
+            lock,
+            signer,
+            content_key,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Encrypts (if applicable) and writes out every full `PAGE_SIZE` chunk
+    /// of `buf`, buffering any remainder until the next write or `finish`.
+    fn append(&mut self, buf: &[u8]) -> Result<()> {
+        self.pending.extend_from_slice(buf);
+        while self.pending.len() >= PAGE_SIZE {
+            let page: Vec<u8> = self.pending.drain(..PAGE_SIZE).collect();
+            self.write_page(&page)?;
+        }
+        Ok(())
+    }
+
+    fn write_page(&mut self, plaintext: &[u8]) -> Result<()> {
+        let page_index = self.stream.head().len() / PAGE_SIZE as u64;
+        let ciphertext = match &self.content_key {
+            None => plaintext.to_vec(),
+            Some(key) => encrypt_page(
+                self.stream.encryption(),
+                key,
+                self.stream.head().id().stream(),
+                page_index,
+                plaintext,
+            )?,
+        };
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(&ciphertext)?;
+        drop(file);
+        self.stream.head.head.len += plaintext.len() as u64;
+        Ok(())
+    }
+
+    /// Re-derives the bao hash/outboard over the ciphertext now on disk.
+    ///
+    /// Called once per append session (from `finish`/`finish_with_head`),
+    /// not once per page: `write_page` only appends bytes and bumps `len`,
+    /// so appending `n` bytes now costs one whole-file rehash here instead
+    /// of rehashing the whole file again on every `PAGE_SIZE` chunk written
+    /// along the way.
+    fn recompute_outboard_and_hash(&mut self) -> Result<()> {
+        let content = std::fs::read(&self.path)?;
+        let (outboard, hash) = bao::encode::outboard(&content);
+        self.stream.outboard = outboard;
+        self.stream.head.head.hash = *hash.as_bytes();
+        Ok(())
+    }
+
+    fn persist(&mut self) -> Result<()> {
+        let id = *self.stream.head().id();
+        self.db.insert(id.as_bytes(), self.stream.to_sled_bytes()?)?;
+        Ok(())
+    }
+}
+
+impl<S> Write for StreamWriter<S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.append(buf)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<K: Signer> StreamWriter<Arc<K>> {
+    /// Flushes any buffered partial final page, signs the resulting head
+    /// with the local signing key, and persists it.
+    pub fn finish(mut self) -> Result<Head> {
+        if !self.pending.is_empty() {
+            let page = std::mem::take(&mut self.pending);
+            self.write_page(&page)?;
+        }
+        self.recompute_outboard_and_hash()?;
+        self.stream.head.sign(&*self.signer)?;
+        self.persist()?;
+        Ok(*self.stream.head())
+    }
+}
+
+impl StreamWriter<()> {
+    /// Flushes any buffered partial final page, then accepts `signed` as
+    /// this stream's new head once it's confirmed to match the content
+    /// that was actually written. `signed` must already have been verified
+    /// by the caller (e.g. `Replicator::pull_from_peer` verifies it against
+    /// the key the stream's rotation chain authorizes at its length before
+    /// writing any bytes).
+    pub fn finish_with_head(mut self, signed: SignedHead) -> Result<Head> {
+        if !self.pending.is_empty() {
+            let page = std::mem::take(&mut self.pending);
+            self.write_page(&page)?;
+        }
+        self.recompute_outboard_and_hash()?;
+        if signed.head().hash() != self.stream.head().hash()
+            || signed.head().len() != self.stream.head().len()
+        {
+            return Err(anyhow::anyhow!(
+                "remote head doesn't match the content that was written"
+            ));
+        }
+        self.stream.head = signed;
+        self.persist()?;
+        Ok(*self.stream.head())
+    }
+}
+
+/// Reads a plaintext byte range `[start, start + len)` of a stream,
+/// decrypting pages on the fly if the stream is encrypted.
+pub struct StreamReader {
+    file: File,
+    encryption: EncryptionType,
+    content_key: Option<ContentKey>,
+    stream: u64,
+    pos: u64,
+    end: u64,
+    page: Vec<u8>,
+    page_start: u64,
+}
+
+impl StreamReader {
+    pub(crate) fn new(
+        path: &Path,
+        stream: &Stream,
+        start: u64,
+        len: u64,
+        passphrase: Option<&[u8]>,
+    ) -> Result<Self> {
+        let content_key = match stream.encryption() {
+            EncryptionType::None => None,
+            _ => Some(ContentKey::derive(
+                passphrase
+                    .ok_or_else(|| anyhow::anyhow!("stream is encrypted, passphrase required"))?,
+                stream.salt(),
+            )?),
+        };
+        Ok(Self {
+            file: File::open(path)?,
+            encryption: stream.encryption(),
+            content_key,
+            stream: stream.head().id().stream(),
+            pos: start,
+            end: start + len,
+            page: Vec::new(),
+            page_start: u64::MAX,
+        })
+    }
+
+    fn fill_page(&mut self) -> Result<()> {
+        let page_index = self.pos / PAGE_SIZE as u64;
+        let page_start = page_index * PAGE_SIZE as u64;
+        let ciphertext_len = ciphertext_page_len(self.encryption, PAGE_SIZE);
+        let offset = page_index * ciphertext_len as u64;
+        self.file.seek(SeekFrom::Start(offset))?;
+        let mut ciphertext = vec![0; ciphertext_len];
+        let n = self.file.read(&mut ciphertext)?;
+        ciphertext.truncate(n);
+        self.page = match &self.content_key {
+            None => ciphertext,
+            Some(key) => decrypt_page(self.encryption, key, self.stream, page_index, &ciphertext)?,
+        };
+        self.page_start = page_start;
+        Ok(())
+    }
+}
+
+impl Read for StreamReader {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos >= self.end {
+            return Ok(0);
+        }
+        let in_page = self.pos >= self.page_start
+            && self.pos < self.page_start + self.page.len() as u64
+            && !self.page.is_empty();
+        if !in_page {
+            self.fill_page()
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+            if self.page.is_empty() {
+                return Ok(0);
+            }
+        }
+        let page_offset = (self.pos - self.page_start) as usize;
+        let available = &self.page[page_offset..];
+        let want = std::cmp::min(out.len() as u64, self.end - self.pos) as usize;
+        let n = std::cmp::min(want, available.len());
+        out[..n].copy_from_slice(&available[..n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
@@ -0,0 +1,91 @@
+//! Keypair generation helpers for a stream's `peer` identity.
+//!
+//! `StreamStorage::open` takes a `Keypair` but the crate has no opinion on
+//! where it comes from; these helpers cover the two common cases of wanting
+//! a memorable [`StreamId`] prefix, or of recovering a peer key
+//! deterministically from a memorized passphrase.
+use ed25519_dalek::Keypair;
+use rand_core::OsRng;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+/// Repeatedly samples keypairs across all available cores until the
+/// base64-URL-no-pad encoding of `public.to_bytes()` (the same encoding
+/// `StreamId`'s `Debug`/`Display` impls use for `peer`) starts with
+/// `prefix`. Returns the matching keypair and the number of keys tried.
+pub fn generate_vanity(prefix: &str) -> (Keypair, u64) {
+    let found: Arc<std::sync::Mutex<Option<Keypair>>> = Arc::new(std::sync::Mutex::new(None));
+    let tried = Arc::new(AtomicU64::new(0));
+    let threads = thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1);
+
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            let found = found.clone();
+            let tried = tried.clone();
+            let prefix = prefix.to_string();
+            scope.spawn(move || {
+                let mut rng = OsRng;
+                loop {
+                    if found.lock().unwrap().is_some() {
+                        return;
+                    }
+                    let keypair = Keypair::generate(&mut rng);
+                    tried.fetch_add(1, Ordering::Relaxed);
+                    let encoded =
+                        base64::encode_config(keypair.public.to_bytes(), base64::URL_SAFE_NO_PAD);
+                    if encoded.starts_with(&prefix) {
+                        *found.lock().unwrap() = Some(keypair);
+                        return;
+                    }
+                }
+            });
+        }
+    });
+
+    let keypair = Arc::try_unwrap(found)
+        .ok()
+        .and_then(|m| m.into_inner().ok())
+        .flatten()
+        .expect("vanity search thread exited without a match");
+    (keypair, tried.load(Ordering::Relaxed))
+}
+
+/// Deterministically derives a peer keypair from a passphrase, so an
+/// operator can reproducibly recover it (and thus the `peer` half of every
+/// `StreamId` they own) from a memorized secret rather than a key file.
+pub fn from_phrase(phrase: &str) -> Keypair {
+    let seed = blake3::hash(phrase.as_bytes());
+    let secret = ed25519_dalek::SecretKey::from_bytes(seed.as_bytes())
+        .expect("blake3 output is a valid ed25519 seed length");
+    let public = (&secret).into();
+    Keypair { secret, public }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_phrase_is_deterministic() {
+        let a = from_phrase("correct horse battery staple");
+        let b = from_phrase("correct horse battery staple");
+        assert_eq!(a.public.to_bytes(), b.public.to_bytes());
+    }
+
+    #[test]
+    fn from_phrase_differs_by_phrase() {
+        let a = from_phrase("correct horse battery staple");
+        let b = from_phrase("hunter2");
+        assert_ne!(a.public.to_bytes(), b.public.to_bytes());
+    }
+
+    #[test]
+    fn vanity_matches_requested_prefix() {
+        let (keypair, _tried) = generate_vanity("A");
+        let encoded = base64::encode_config(keypair.public.to_bytes(), base64::URL_SAFE_NO_PAD);
+        assert!(encoded.starts_with('A'));
+    }
+}
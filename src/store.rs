@@ -1,5 +1,9 @@
+use crate::encryption::ContentKey;
 use crate::stream::StreamLock;
-use crate::{SignedHead, Slice, Stream, StreamId, StreamReader, StreamWriter};
+use crate::{
+    EncryptionType, Rotation, SignedHead, Signer, Slice, Stream, StreamId, StreamReader,
+    StreamWriter,
+};
 use anyhow::Result;
 use bao::encode::SliceExtractor;
 use ed25519_dalek::Keypair;
@@ -56,16 +60,19 @@ impl<T> From<&ZeroCopy<T>> for sled::IVec {
     }
 }
 
-pub struct StreamStorage {
+pub struct StreamStorage<K: Signer = Keypair> {
     db: sled::Db,
     dir: PathBuf,
-    key: Arc<Keypair>,
+    key: Arc<K>,
     locks: Arc<Mutex<FnvHashSet<StreamId>>>,
     paths: FnvHashMap<StreamId, PathBuf>,
 }
 
-impl StreamStorage {
-    pub fn open(dir: &Path, key: Keypair) -> Result<Self> {
+impl<K: Signer> StreamStorage<K> {
+    /// Opens (or creates) a stream store at `dir`, signing local heads with
+    /// `key`. `key` may be a raw `Keypair` or anything implementing
+    /// [`Signer`] (an SSH agent, an HSM, a remote signing service, ...).
+    pub fn open(dir: &Path, key: K) -> Result<Self> {
         let db = sled::open(dir.join("db"))?;
         let dir = dir.join("streams");
         std::fs::create_dir(&dir)?;
@@ -80,7 +87,8 @@ impl StreamStorage {
 
     fn get_stream(&self, id: &StreamId) -> Result<Option<ZeroCopy<Stream>>> {
         if let Some(bytes) = self.db.get(id.as_bytes())? {
-            Ok(Some(ZeroCopy::new(bytes)))
+            let body = crate::stream::check_stream_schema(&bytes)?;
+            Ok(Some(ZeroCopy::new(sled::IVec::from(body))))
         } else {
             Ok(None)
         }
@@ -100,55 +108,148 @@ impl StreamStorage {
         self.paths.get(id).unwrap()
     }
 
-    pub fn streams(&self) -> impl Iterator<Item = Result<(StreamId, SignedHead)>> {
+    #[allow(clippy::type_complexity)]
+    pub fn streams(
+        &self,
+    ) -> impl Iterator<Item = Result<(StreamId, SignedHead, Vec<Rotation>, EncryptionType, [u8; 32])>>
+    {
         self.db.iter().map(|res| {
             let (k, v) = res?;
             let id = ZeroCopy::<StreamId>::new(k);
-            let stream = ZeroCopy::<Stream>::new(v);
+            let body = crate::stream::check_stream_schema(&v)?;
+            let stream = ZeroCopy::<Stream>::new(sled::IVec::from(body));
             let head = stream.head.deserialize(&mut Infallible)?;
-            Ok((id.to_inner(), head))
+            let rotations = stream.rotations.deserialize(&mut Infallible)?;
+            let encryption = stream.encryption.deserialize(&mut Infallible)?;
+            let salt = stream.salt;
+            Ok((id.to_inner(), head, rotations, encryption, salt))
         })
     }
 
     pub fn create_local_stream(&self) -> Result<StreamId> {
-        let peer = self.key.public.to_bytes();
+        self.create_local_stream_with_encryption(EncryptionType::None)
+    }
+
+    /// Creates a local stream whose content will be encrypted at rest with
+    /// `encryption`. A fresh random salt is generated for the stream;
+    /// `append_local_stream` and `slice` derive the matching content key
+    /// (see `ContentKey::derive`) from the passphrase passed to them and
+    /// this salt.
+    pub fn create_local_stream_with_encryption(
+        &self,
+        encryption: EncryptionType,
+    ) -> Result<StreamId> {
+        let peer = self.key.public_key().to_bytes();
         let stream = self
             .db
             .transaction::<_, _, sled::Error>(|tx| Ok(tx.generate_id()?))?;
         let id = StreamId::new(peer, stream);
-        self.create_replicated_stream(&id)?;
+        self.create_replicated_stream_with_encryption(&id, encryption)?;
         Ok(id)
     }
 
     pub fn create_replicated_stream(&self, id: &StreamId) -> Result<()> {
-        let stream = Stream::new(*id).to_bytes()?.into_vec();
-        self.db.insert(id.as_bytes(), &stream[..])?;
+        self.create_replicated_stream_with_encryption(id, EncryptionType::None)
+    }
+
+    pub fn create_replicated_stream_with_encryption(
+        &self,
+        id: &StreamId,
+        encryption: EncryptionType,
+    ) -> Result<()> {
+        let stream = Stream::new_with_encryption(*id, encryption);
+        self.create_stream_record(id, stream)
+    }
+
+    /// Like [`Self::create_replicated_stream_with_encryption`], but pins the
+    /// content-key salt instead of generating a fresh random one. The
+    /// replicator uses this when first pulling an encrypted stream from a
+    /// peer: the local replica's ciphertext is byte-for-byte the peer's, so
+    /// it must be recorded with the peer's salt, not a new one, or a
+    /// passphrase that derives the right key on the origin won't derive it
+    /// here.
+    pub(crate) fn create_replicated_stream_with_salt(
+        &self,
+        id: &StreamId,
+        encryption: EncryptionType,
+        salt: [u8; 32],
+    ) -> Result<()> {
+        let stream = Stream::new_with_encryption_and_salt(*id, encryption, salt);
+        self.create_stream_record(id, stream)
+    }
+
+    fn create_stream_record(&self, id: &StreamId, stream: Stream) -> Result<()> {
+        self.db.insert(id.as_bytes(), stream.to_sled_bytes()?)?;
+        // `append_local_stream`/`append_replicated_stream` append to this
+        // file and `extract`/`slice` open it unconditionally, so it must
+        // exist from the moment the stream is created, even before any
+        // content has been written.
+        File::create(self.dir.join(id.to_string()))?;
         Ok(())
     }
 
-    pub fn append_local_stream(&mut self, id: &StreamId) -> Result<StreamWriter<Arc<Keypair>>> {
+    /// Opens `id` for appending, signing new heads with the local key.
+    /// `passphrase` is required (and used to derive the content key) when
+    /// the stream was created with an `EncryptionType` other than `None`.
+    pub fn append_local_stream(
+        &mut self,
+        id: &StreamId,
+        passphrase: Option<&[u8]>,
+    ) -> Result<StreamWriter<Arc<K>>> {
         let lock = self.lock_stream(id.clone())?;
         let stream = if let Some(stream) = self.get_stream(id)? {
-            stream
+            stream.to_inner()
         } else {
             return Err(anyhow::anyhow!("stream doesn't exist"));
         };
+        let content_key = match stream.encryption() {
+            EncryptionType::None => None,
+            _ => Some(ContentKey::derive(
+                passphrase
+                    .ok_or_else(|| anyhow::anyhow!("stream is encrypted, passphrase required"))?,
+                stream.salt(),
+            )?),
+        };
         let db = self.db.clone();
         let key = self.key.clone();
         let path = self.stream_path(id);
-        Ok(StreamWriter::new(path, stream.to_inner(), lock, db, key)?)
+        Ok(StreamWriter::new(path, stream, lock, db, key, content_key))
+    }
+
+    /// Rotates `id`'s active signing key from the current key to `new_key`,
+    /// signing a [`Rotation`] record with the *old* key and persisting it
+    /// into the stream's rotation chain. Local appends made after this call
+    /// are signed with `new_key`; the stream's `StreamId` (and thus its
+    /// address) is unaffected.
+    pub fn rotate_key(&mut self, id: &StreamId, new_key: K) -> Result<()> {
+        let _lock = self.lock_stream(id.clone())?;
+        let mut stream = if let Some(stream) = self.get_stream(id)? {
+            stream.to_inner()
+        } else {
+            return Err(anyhow::anyhow!("stream doesn't exist"));
+        };
+        let rotation = Rotation::sign(&*self.key, new_key.public_key(), stream.head().len())?;
+        stream.rotations.push(rotation);
+        self.db.insert(id.as_bytes(), stream.to_sled_bytes()?)?;
+        self.key = Arc::new(new_key);
+        Ok(())
     }
 
+    /// Opens `id` for appending bytes pulled from a replication peer. These
+    /// bytes are already the stream's real on-disk content (ciphertext, if
+    /// the stream is encrypted), so -- unlike [`Self::append_local_stream`]
+    /// -- no passphrase is needed: the writer never re-encrypts what it's
+    /// given, it just writes it through and accepts the peer's signed head.
     pub fn append_replicated_stream(&mut self, id: &StreamId) -> Result<StreamWriter<()>> {
         let lock = self.lock_stream(id.clone())?;
         let stream = if let Some(stream) = self.get_stream(id)? {
-            stream
+            stream.to_inner()
         } else {
             return Err(anyhow::anyhow!("stream doesn't exist"));
         };
         let db = self.db.clone();
         let path = self.stream_path(id);
-        Ok(StreamWriter::new(path, stream.to_inner(), lock, db, ())?)
+        Ok(StreamWriter::new(path, stream, lock, db, (), None))
     }
 
     pub fn remove_stream(&mut self, id: &StreamId) -> Result<()> {
@@ -160,13 +261,25 @@ impl StreamStorage {
         Ok(())
     }
 
-    pub fn slice(&mut self, id: &StreamId, start: u64, len: u64) -> Result<StreamReader> {
+    pub fn slice(
+        &mut self,
+        id: &StreamId,
+        start: u64,
+        len: u64,
+        passphrase: Option<&[u8]>,
+    ) -> Result<StreamReader> {
         let stream = if let Some(stream) = self.get_stream(id)? {
             stream
         } else {
             return Err(anyhow::anyhow!("stream doesn't exist"));
         };
-        StreamReader::new(self.stream_path(id), &stream.head.head, start, len)
+        StreamReader::new(
+            self.stream_path(id),
+            &stream.to_inner(),
+            start,
+            len,
+            passphrase,
+        )
     }
 
     pub fn extract(
@@ -190,3 +303,62 @@ impl StreamStorage {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    /// A fresh scratch directory for a single test's `StreamStorage`.
+    fn temp_storage_dir() -> PathBuf {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let dir = std::env::temp_dir().join(format!(
+            "blake-streams-store-test-{}-{}",
+            std::process::id(),
+            n
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// `rotate_key` hands off signing authority to a new key, and a head
+    /// appended after rotation is signed with it rather than the genesis
+    /// key. `Stream::verify_head` must walk the rotation chain to accept
+    /// such a head; checking it against the genesis key directly (as
+    /// `SignedHead::verify`/`verify_self` do) must fail.
+    #[test]
+    fn rotate_key_then_append_verifies_against_rotated_key() {
+        let genesis = Keypair::generate(&mut rand_core::OsRng);
+        let genesis_public = genesis.public;
+        let rotated = Keypair::generate(&mut rand_core::OsRng);
+
+        let dir = temp_storage_dir();
+        let mut storage = StreamStorage::open(&dir, genesis).unwrap();
+        let id = storage.create_local_stream().unwrap();
+
+        let mut writer = storage.append_local_stream(&id, None).unwrap();
+        writer.write_all(b"before rotation").unwrap();
+        writer.finish().unwrap();
+
+        storage.rotate_key(&id, rotated).unwrap();
+
+        let mut writer = storage.append_local_stream(&id, None).unwrap();
+        writer.write_all(b"after rotation").unwrap();
+        writer.finish().unwrap();
+
+        let stream = storage.get_stream(&id).unwrap().unwrap().to_inner();
+        assert_eq!(stream.rotations().len(), 1);
+        assert_ne!(stream.active_key().unwrap(), genesis_public);
+
+        stream.verify_head(&stream.head).unwrap();
+        assert!(stream
+            .head
+            .verify_with_key(&id, &genesis_public)
+            .is_err());
+
+        drop(storage);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}